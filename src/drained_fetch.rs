@@ -0,0 +1,45 @@
+use futures::{StreamExt, TryStreamExt};
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteRow};
+use sqlx::{Executor, Sqlite};
+
+use crate::error::Error;
+
+/// Extension trait that fully drains a query's result stream before handing
+/// back its first row.
+///
+/// SQLite's `RETURNING` rows aren't visible to other connections until the
+/// statement that produced them finishes streaming, so leaving any rows
+/// unread — as a bare `fetch_one`/`fetch_optional` does — holds a write lock
+/// open and lets readers observe writes out of order. Every single-row
+/// `RETURNING` accessor in this crate (`insert`, `full_insert`, `insert_once`,
+/// `insert_typed`) goes through [`fetch_drained`](Self::fetch_drained)
+/// instead of sqlx's own fetch methods, so that invariant can't be broken by
+/// accident. Multi-row accessors (`select`, `select_typed`) stream every row
+/// by design and don't need draining.
+// The returned future carries no `Send` bound (desugaring to
+// `-> impl Future + Send` would add one); every call site in this crate
+// awaits it in place rather than moving it to another thread.
+#[allow(async_fn_in_trait)]
+pub trait DrainedFetch<'q> {
+    async fn fetch_drained<E>(self, executor: E) -> Result<Option<SqliteRow>, Error>
+    where
+        E: Executor<'q, Database = Sqlite>;
+}
+
+impl<'q> DrainedFetch<'q> for Query<'q, Sqlite, SqliteArguments<'q>> {
+    async fn fetch_drained<E>(self, executor: E) -> Result<Option<SqliteRow>, Error>
+    where
+        E: Executor<'q, Database = Sqlite>,
+    {
+        let mut results = self.fetch(executor).fuse();
+
+        let first = results.try_next().await?;
+
+        while let Some(result) = results.next().await {
+            result?;
+        }
+
+        Ok(first)
+    }
+}
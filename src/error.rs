@@ -0,0 +1,34 @@
+use std::fmt;
+
+/// Errors returned by [`crate::DatabaseAccess`](super::DatabaseAccess).
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying connection, query, or transaction failed.
+    Sqlx(sqlx::Error),
+    /// An insert's `RETURNING` clause didn't yield the row it promised.
+    MissingRow,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlx(err) => write!(f, "sqlite query failed: {err}"),
+            Error::MissingRow => write!(f, "insert did not return the expected row"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sqlx(err) => Some(err),
+            Error::MissingRow => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        Error::Sqlx(err)
+    }
+}
@@ -0,0 +1,38 @@
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Decode, Row, Sqlite, Type};
+
+/// Extracts a typed value out of a [`SqliteRow`] by positional column index.
+///
+/// This mirrors the tuple-based extractor pattern used by `sqlx::FromRow`,
+/// but stays local to this crate so `DatabaseAccess` can return plain tuples
+/// from `RETURNING`/`SELECT` queries without callers juggling untyped
+/// `row.get(0)` indices themselves.
+pub trait FromRow {
+    fn from_row(row: &SqliteRow) -> Self;
+}
+
+/// Pulls a single typed column out of `row` at `index`.
+pub fn extract<'r, T>(row: &'r SqliteRow, index: usize) -> T
+where
+    T: Decode<'r, Sqlite> + Type<Sqlite>,
+{
+    row.get(index)
+}
+
+macro_rules! impl_from_row_for_tuple {
+    ($($idx:tt => $ty:ident),+) => {
+        impl<$($ty),+> FromRow for ($($ty,)+)
+        where
+            $($ty: for<'r> Decode<'r, Sqlite> + Type<Sqlite>,)+
+        {
+            fn from_row(row: &SqliteRow) -> Self {
+                ($(extract::<$ty>(row, $idx),)+)
+            }
+        }
+    };
+}
+
+impl_from_row_for_tuple!(0 => A);
+impl_from_row_for_tuple!(0 => A, 1 => B);
+impl_from_row_for_tuple!(0 => A, 1 => B, 2 => C);
+impl_from_row_for_tuple!(0 => A, 1 => B, 2 => C, 3 => D);
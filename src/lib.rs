@@ -1,31 +1,45 @@
+pub mod drained_fetch;
+mod error;
+mod from_row;
+mod migrations;
+
 use std::path::Path;
 use std::time::Duration;
 
-use futures::{StreamExt, TryStreamExt};
-use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
-use sqlx::{Row, SqlitePool};
-
-const DB_INIT_SQL: &str = r#"
-CREATE TABLE IF NOT EXISTS my_table (
-    id INTEGER PRIMARY KEY,
-    batch_id INTEGER
-);
+use futures::TryStreamExt;
+use sqlx::query::Query;
+use sqlx::sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Row, Sqlite, SqlitePool};
 
-CREATE INDEX IF NOT EXISTS my_table_name ON my_table (
-    batch_id ASC,
-    id ASC
-);
-"#;
+pub use drained_fetch::DrainedFetch;
+pub use error::Error;
+use from_row::{extract, FromRow};
 
 struct DatabaseAccess {
     pool: SqlitePool,
 }
 
 impl DatabaseAccess {
-    async fn connect(db_file: impl AsRef<Path>) -> DatabaseAccess {
-        let pool = SqlitePoolOptions::new()
-            .acquire_timeout(Duration::from_secs(600))
-            .max_connections(50)
+    async fn connect(db_file: impl AsRef<Path>) -> Result<DatabaseAccess, Error> {
+        let is_memory = matches!(
+            db_file.as_ref().to_str(),
+            Some(":memory:") | Some("sqlite::memory:")
+        );
+
+        let mut pool_options = SqlitePoolOptions::new().acquire_timeout(Duration::from_secs(600));
+        pool_options = if is_memory {
+            // A pooled in-memory SQLite database is private per-connection, so
+            // recycling or spreading across connections silently loses writes.
+            // Pin the pool to the single connection that owns the database.
+            pool_options
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            pool_options.max_connections(50)
+        };
+
+        let pool = pool_options
             .connect_with(
                 SqliteConnectOptions::new()
                     .filename(db_file)
@@ -34,52 +48,92 @@ impl DatabaseAccess {
                     .journal_mode(SqliteJournalMode::Wal)
                     .busy_timeout(Duration::from_secs(600)),
             )
-            .await
-            .unwrap();
+            .await?;
 
-        DatabaseAccess::init_db(&pool).await;
+        migrations::run_migrations(&pool).await?;
 
-        DatabaseAccess { pool }
+        Ok(DatabaseAccess { pool })
     }
 
-    async fn init_db(pool: &SqlitePool) {
-        let mut results = sqlx::query(DB_INIT_SQL).execute_many(pool).await;
-        while results.try_next().await.unwrap().is_some() {}
+    async fn insert(&self, batch_id: i64) -> Result<i64, Error> {
+        let query = sqlx::query("INSERT INTO my_table (batch_id) VALUES (?) RETURNING id").bind(batch_id);
+        let row = query.fetch_drained(&self.pool).await?.ok_or(Error::MissingRow)?;
+        Ok(extract(&row, 0))
     }
 
-    async fn insert(&self, batch_id: i64) -> i64 {
-        let result = sqlx::query("INSERT INTO my_table (batch_id) VALUES (?) RETURNING id")
-            .bind(batch_id)
-            .fetch_one(&self.pool)
-            .await
-            .unwrap();
-        result.get(0)
+    /// Alias for [`insert`](Self::insert), kept for callers that spelled out
+    /// "full" to mean "drains the `RETURNING` stream" back when that wasn't
+    /// the default behavior of every insert here.
+    async fn full_insert(&self, batch_id: i64) -> Result<i64, Error> {
+        self.insert(batch_id).await
     }
 
-    async fn full_insert(&self, batch_id: i64) -> i64 {
-        let mut results = sqlx::query("INSERT INTO my_table (batch_id) VALUES (?) RETURNING id")
-            .bind(batch_id)
-            .fetch(&self.pool)
-            .fuse();
+    /// Inserts `count` rows for `batch_id` inside a single transaction,
+    /// fully draining each `RETURNING` statement before issuing the next so
+    /// the WAL is only fsynced once per batch. If any insert fails the whole
+    /// batch is rolled back, so callers never observe a partial batch.
+    async fn insert_batch(&self, batch_id: i64, count: usize) -> Result<Vec<i64>, Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(count);
 
-        let Some(row) = results.try_next().await.unwrap() else {
-            panic!("insert failed");
-        };
+        for _ in 0..count {
+            let query = sqlx::query("INSERT INTO my_table (batch_id) VALUES (?) RETURNING id").bind(batch_id);
+            let row = query.fetch_drained(&mut *tx).await?.ok_or(Error::MissingRow)?;
+            ids.push(row.get(0));
+        }
+
+        tx.commit().await?;
+        Ok(ids)
+    }
+
+    /// Inserts a row for `batch_id` keyed on `event_id`, giving exactly-once
+    /// semantics for replayed/duplicated upstream events. A first-seen
+    /// `event_id` returns its new row id; a duplicate is silently skipped
+    /// and returns `None`, without creating a second row.
+    async fn insert_once(&self, batch_id: i64, event_id: &str) -> Result<Option<i64>, Error> {
+        let query = sqlx::query(
+            "INSERT INTO my_table (batch_id, event_id) VALUES (?, ?) \
+             ON CONFLICT(event_id) DO NOTHING RETURNING id",
+        )
+        .bind(batch_id)
+        .bind(event_id);
 
-        while results.next().await.is_some() {}
-        row.get(0)
+        let row = query.fetch_drained(&self.pool).await?;
+        Ok(row.map(|row| row.get(0)))
     }
 
-    async fn select(&self, batch_id: i64) -> Vec<i64> {
+    async fn select(&self, batch_id: i64) -> Result<Vec<i64>, Error> {
         let mut r = sqlx::query("SELECT id FROM my_table where batch_id = ? ORDER BY id")
             .bind(batch_id)
             .fetch(&self.pool);
 
         let mut result = Vec::new();
-        while let Some(row) = r.try_next().await.unwrap() {
-            result.push(row.get(0));
+        while let Some(row) = r.try_next().await? {
+            result.push(extract(&row, 0));
         }
-        result
+        Ok(result)
+    }
+
+    /// Like [`insert`](Self::insert), but runs the caller-supplied `query`
+    /// (typically a `RETURNING` statement selecting more than one column)
+    /// and extracts every column into `T`, instead of assuming a single
+    /// `i64` id column.
+    async fn insert_typed<'q, T: FromRow>(&self, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Result<T, Error> {
+        let row = query.fetch_drained(&self.pool).await?.ok_or(Error::MissingRow)?;
+        Ok(T::from_row(&row))
+    }
+
+    /// Like [`select`](Self::select), but runs the caller-supplied `query`
+    /// and extracts every selected column into `T`, instead of assuming a
+    /// single `i64` id column.
+    async fn select_typed<'q, T: FromRow>(&self, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Result<Vec<T>, Error> {
+        let mut r = query.fetch(&self.pool);
+
+        let mut result = Vec::new();
+        while let Some(row) = r.try_next().await? {
+            result.push(T::from_row(&row));
+        }
+        Ok(result)
     }
 }
 
@@ -99,7 +153,7 @@ mod tests {
     async fn test_db() -> Fixture {
         let tempdir = tempfile::tempdir().unwrap();
         let db_file = tempdir.path().join("catalog.db");
-        let db_access = DatabaseAccess::connect(db_file).await;
+        let db_access = DatabaseAccess::connect(db_file).await.unwrap();
 
         Fixture {
             _tempdir: tempdir,
@@ -107,25 +161,102 @@ mod tests {
         }
     }
 
+    /// Regression test for the bug `insert` used to have before it was
+    /// routed through `DrainedFetch`: a bare `fetch_one` left the RETURNING
+    /// statement's stream undrained, so a `select` right after could miss
+    /// rows whose insert had already returned. This used to fail intermittently
+    /// (hence the old test name "flaky"); it's now deterministic.
     #[rstest]
     #[awt]
     #[tokio::test]
-    async fn flaky(#[future] test_db: Fixture) {
+    async fn insert_is_no_longer_flaky(#[future] test_db: Fixture) {
         for i in 0..10000 {
-            let id1 = test_db.db_access.insert(i).await;
-            let id2 = test_db.db_access.insert(i).await;
-            assert_eq!(test_db.db_access.select(i).await, vec![id1, id2])
+            let id1 = test_db.db_access.insert(i).await.unwrap();
+            let id2 = test_db.db_access.insert(i).await.unwrap();
+            assert_eq!(test_db.db_access.select(i).await.unwrap(), vec![id1, id2])
         }
     }
 
+    #[rstest]
+    #[awt]
+    #[tokio::test]
+    async fn typed_roundtrip(#[future] test_db: Fixture) {
+        let insert = sqlx::query("INSERT INTO my_table (batch_id) VALUES (?) RETURNING id, batch_id").bind(7);
+        let row: (i64, i64) = test_db.db_access.insert_typed(insert).await.unwrap();
+        assert_eq!(row.1, 7);
+
+        let select =
+            sqlx::query("SELECT id, batch_id FROM my_table WHERE batch_id = ? ORDER BY id").bind(7);
+        assert_eq!(
+            test_db.db_access.select_typed::<(i64, i64)>(select).await.unwrap(),
+            vec![row]
+        );
+    }
+
     #[rstest]
     #[awt]
     #[tokio::test]
     async fn works(#[future] test_db: Fixture) {
         for i in 0..10000 {
-            let id1 = test_db.db_access.full_insert(i).await;
-            let id2 = test_db.db_access.full_insert(i).await;
-            assert_eq!(test_db.db_access.select(i).await, vec![id1, id2])
+            let id1 = test_db.db_access.full_insert(i).await.unwrap();
+            let id2 = test_db.db_access.full_insert(i).await.unwrap();
+            assert_eq!(test_db.db_access.select(i).await.unwrap(), vec![id1, id2])
         }
     }
+
+    #[tokio::test]
+    async fn in_memory_pool() {
+        let db_access = DatabaseAccess::connect(":memory:").await.unwrap();
+
+        let id1 = db_access.full_insert(1).await.unwrap();
+        let id2 = db_access.full_insert(1).await.unwrap();
+        assert_eq!(db_access.select(1).await.unwrap(), vec![id1, id2]);
+    }
+
+    #[rstest]
+    #[awt]
+    #[tokio::test]
+    async fn insert_batch_is_atomic_and_visible(#[future] test_db: Fixture) {
+        let ids = test_db.db_access.insert_batch(3, 50).await.unwrap();
+        assert_eq!(ids.len(), 50);
+        assert_eq!(test_db.db_access.select(3).await.unwrap(), ids);
+    }
+
+    /// Regression test for pool poisoning: a `RETURNING` statement that
+    /// violates a constraint errors when [`DrainedFetch::fetch_drained`]
+    /// steps it for its first row, not at prepare time. The connection it ran
+    /// on must still come back to the pool clean.
+    #[rstest]
+    #[awt]
+    #[tokio::test]
+    async fn pool_recovers_after_failed_query(#[future] test_db: Fixture) {
+        test_db.db_access.insert_once(-1, "seed").await.unwrap();
+
+        for i in 0..50 {
+            let failure = sqlx::query(
+                "INSERT INTO my_table (batch_id, event_id) VALUES (?, ?) RETURNING id",
+            )
+            .bind(i)
+            .bind("seed")
+            .fetch_drained(&test_db.db_access.pool)
+            .await;
+            assert!(failure.is_err());
+
+            let id = test_db.db_access.full_insert(i).await.unwrap();
+            assert_eq!(test_db.db_access.select(i).await.unwrap(), vec![id]);
+        }
+    }
+
+    #[rstest]
+    #[awt]
+    #[tokio::test]
+    async fn insert_once_is_idempotent(#[future] test_db: Fixture) {
+        let id = test_db.db_access.insert_once(1, "event-a").await.unwrap();
+        assert!(id.is_some());
+
+        let duplicate = test_db.db_access.insert_once(1, "event-a").await.unwrap();
+        assert_eq!(duplicate, None);
+
+        assert_eq!(test_db.db_access.select(1).await.unwrap(), vec![id.unwrap()]);
+    }
 }
@@ -0,0 +1,164 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::{Row, SqlitePool};
+
+use crate::error::Error;
+
+/// A single schema change, applied at most once and in `version` order.
+pub(crate) struct Migration {
+    pub version: u32,
+    pub up: &'static str,
+}
+
+/// The ordered set of migrations this binary knows how to apply. Append new
+/// migrations to the end; never edit or remove an already-released one.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        up: r#"
+CREATE TABLE IF NOT EXISTS my_table (
+    id INTEGER PRIMARY KEY,
+    batch_id INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS my_table_name ON my_table (
+    batch_id ASC,
+    id ASC
+);
+"#,
+    },
+    Migration {
+        version: 2,
+        up: r#"
+ALTER TABLE my_table ADD COLUMN event_id TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS my_table_event_id ON my_table (event_id);
+"#,
+    },
+];
+
+/// Brings `pool`'s schema up to the latest known `Migration`, tracking
+/// progress in a `schema_migrations` table so repeated calls are no-ops past
+/// the first. Runs inside a single transaction: either every pending
+/// migration lands, or none do.
+pub(crate) async fn run_migrations(pool: &SqlitePool) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            applied_at INTEGER
+        )",
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let current_version: i64 = sqlx::query("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")
+        .fetch_one(&mut *tx)
+        .await?
+        .get(0);
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| i64::from(migration.version) > current_version)
+    {
+        sqlx::raw_sql(migration.up).execute(&mut *tx).await?;
+
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        sqlx::query("INSERT INTO schema_migrations (version, applied_at) VALUES (?, ?)")
+            .bind(i64::from(migration.version))
+            .bind(applied_at)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    async fn in_memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    async fn applied_versions(pool: &SqlitePool) -> Vec<i64> {
+        let mut rows = sqlx::query("SELECT version FROM schema_migrations ORDER BY version")
+            .fetch(pool);
+        let mut versions = Vec::new();
+        while let Some(row) = rows.try_next().await.unwrap() {
+            versions.push(row.get(0));
+        }
+        versions
+    }
+
+    #[tokio::test]
+    async fn migrates_from_empty_db() {
+        let pool = in_memory_pool().await;
+
+        run_migrations(&pool).await.unwrap();
+
+        assert_eq!(
+            applied_versions(&pool).await,
+            MIGRATIONS.iter().map(|m| i64::from(m.version)).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn rerunning_migrations_is_a_noop() {
+        let pool = in_memory_pool().await;
+
+        run_migrations(&pool).await.unwrap();
+        run_migrations(&pool).await.unwrap();
+
+        assert_eq!(
+            applied_versions(&pool).await,
+            MIGRATIONS.iter().map(|m| i64::from(m.version)).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn migrates_from_a_partially_migrated_db() {
+        let pool = in_memory_pool().await;
+
+        // Simulate a database an older binary already brought to v1, without
+        // going through `run_migrations`, so the only `Migration` it should
+        // see as pending here is v2.
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                applied_at INTEGER
+            )",
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        sqlx::raw_sql(MIGRATIONS[0].up).execute(&mut *tx).await.unwrap();
+        sqlx::query("INSERT INTO schema_migrations (version, applied_at) VALUES (1, 0)")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        run_migrations(&pool).await.unwrap();
+
+        assert_eq!(
+            applied_versions(&pool).await,
+            MIGRATIONS.iter().map(|m| i64::from(m.version)).collect::<Vec<_>>()
+        );
+    }
+}